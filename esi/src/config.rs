@@ -0,0 +1,81 @@
+use fastly::http::header::HeaderName;
+use std::time::Duration;
+
+/// Which of a request's headers to forward onto fragment subrequests.
+#[derive(Debug, Clone)]
+pub enum HeaderList {
+    /// Forward only the named headers.
+    Allow(Vec<HeaderName>),
+    /// Forward everything except the named headers.
+    Deny(Vec<HeaderName>),
+}
+
+impl Default for HeaderList {
+    /// Forwards every header, matching today's behavior.
+    fn default() -> Self {
+        HeaderList::Deny(Vec::new())
+    }
+}
+
+/// Controls which headers cross the boundary between the original request/response and
+/// fragment subrequests, so that propagation is deterministic and auditable instead of
+/// all-or-nothing.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicy {
+    /// Which headers of the original request (e.g. `Cookie`, `Authorization`, custom geo
+    /// headers) are forwarded onto each fragment subrequest. `Host` is always set to the
+    /// fragment's own host regardless of this policy.
+    pub forward_request_headers: HeaderList,
+    /// Fragment response headers (e.g. `Cache-Control`, `Vary`, `Set-Cookie`) to merge onto
+    /// the outer document response before it is sent to the client. The first fragment to
+    /// set a given header wins; later fragments don't overwrite it. All values of a
+    /// multi-valued header (e.g. repeated `Set-Cookie`) from that fragment are kept.
+    ///
+    /// Merging only takes effect when [`Configuration::buffer_document`] is `true`. When the
+    /// document is streamed, its headers are sent before any fragment resolves, and these
+    /// headers are not merged.
+    pub merge_response_headers: Vec<HeaderName>,
+}
+
+/// Configuration for an ESI [`Processor`](crate::Processor).
+#[derive(Debug, Clone)]
+pub struct Configuration {
+    /// The XML namespace used to identify ESI tags, e.g. `esi` for `<esi:include>`.
+    pub namespace: String,
+    /// The maximum time to wait for a fragment request to complete before treating it as
+    /// failed. Can be overridden per-include via the `timeout` attribute on `<esi:include>`.
+    ///
+    /// This crate doesn't dispatch subrequests itself — it hands the resolved value to the
+    /// `request_handler` passed to [`Processor::execute_esi`](crate::Processor::execute_esi),
+    /// which owns backend selection and so is the only place that can actually enforce it
+    /// (this SDK only exposes fetch timeouts at backend configuration, not per request).
+    pub timeout: Option<Duration>,
+    /// When `true`, the processed document is fully buffered in memory before being sent to
+    /// the client, so that a top-level timeout or error can still produce a proper error
+    /// status and body. When `false` (the default), the document is streamed to the client
+    /// as it is processed, which is more memory-efficient but means errors encountered
+    /// partway through can only be appended as plain text.
+    pub buffer_document: bool,
+    /// The maximum number of `esi:include` subrequests to have in flight at once, across the
+    /// whole document (including fragments nested inside other fragments), not per fragment.
+    /// Sibling includes are dispatched concurrently as they're encountered, up to this limit,
+    /// so that N includes no longer cost N round-trip latencies in sequence. A value of `0`
+    /// is treated as `1` by [`Processor::new`](crate::Processor::new), since it would
+    /// otherwise leave no slot free for any include to ever proceed.
+    pub max_concurrent_includes: usize,
+    /// Which request headers to forward to fragment subrequests, and which fragment
+    /// response headers to merge back onto the outer document response.
+    pub header_policy: HeaderPolicy,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            namespace: String::from("esi"),
+            timeout: None,
+            buffer_document: false,
+            max_concurrent_includes: 10,
+            header_policy: HeaderPolicy::default(),
+        }
+    }
+}
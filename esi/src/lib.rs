@@ -1,15 +1,18 @@
 mod config;
 mod parse;
 
-pub use crate::config::Configuration;
+pub use crate::config::{Configuration, HeaderList, HeaderPolicy};
 use crate::parse::{parse_tags, Event, Tag};
-use fastly::http::body::StreamingBody;
-use fastly::http::header;
-use fastly::http::request::SendError;
-use fastly::{Body, Request, Response};
+use fastly::http::header::{self, HeaderName, HeaderValue};
+use fastly::http::request::{PendingRequest, SendError, SendErrorCause};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
 use log::{debug, error, warn};
+use quick_xml::events::Event as XmlEvent;
 use quick_xml::{Reader, Writer};
-use std::io::Write;
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -27,6 +30,8 @@ pub enum ExecutionError {
     RequestError(#[from] SendError),
     #[error("received unexpected status code for fragment: {0}")]
     UnexpectedStatus(u16),
+    #[error("fragment request to `{0}` timed out")]
+    FragmentTimeout(String),
     #[error("unknown error")]
     Unknown,
 }
@@ -39,35 +44,251 @@ pub struct Processor {
 }
 
 impl Processor {
-    pub fn new(configuration: Configuration) -> Self {
+    pub fn new(mut configuration: Configuration) -> Self {
+        // A cap of 0 would leave no slot ever free: the dispatch loop would spin forever
+        // waiting on an empty queue instead of making progress, so treat it as "no
+        // concurrency" rather than hanging.
+        if configuration.max_concurrent_includes == 0 {
+            configuration.max_concurrent_includes = 1;
+        }
+        // Merged response headers are only ever applied to the client response in
+        // `execute_esi`'s `buffer_document` branch — in the default streaming path, headers
+        // have already been sent to the client before any fragment resolves, so a policy set
+        // without `buffer_document` would otherwise silently do nothing.
+        if !configuration.header_policy.merge_response_headers.is_empty()
+            && !configuration.buffer_document
+        {
+            warn!(
+                "header_policy.merge_response_headers is set but buffer_document is false; \
+                 merged fragment response headers will never reach the client"
+            );
+        }
         Self { configuration }
     }
 }
 
+/// An `esi:include` whose subrequest has already been dispatched and is fetching
+/// concurrently with its siblings, waiting to be resolved in document order.
+struct PendingInclude {
+    src: String,
+    alt: Option<String>,
+    continue_on_error: bool,
+    timeout: Option<Duration>,
+    pending: Result<PendingRequest>,
+}
+
+/// A unit of the fragment's output, queued so that concurrently-fetching siblings can be
+/// resolved strictly in document order.
+enum Segment {
+    Xml(XmlEvent<'static>),
+    Include(Box<PendingInclude>),
+    TryStart,
+    TryEnd,
+    AttemptStart,
+    AttemptEnd,
+    ExceptStart,
+    ExceptEnd,
+}
+
+/// Fragment response headers collected so far, to be merged onto the outer document
+/// response. The first fragment to set a given header wins.
+type MergedHeaders = Vec<(HeaderName, HeaderValue)>;
+
+/// Which half of an `esi:try` block an in-progress output buffer belongs to.
+#[derive(Clone, Copy)]
+enum BufferKind {
+    Attempt,
+    Except,
+}
+
+/// Bookkeeping for one (possibly nested) `esi:try` block.
+#[derive(Default)]
+struct TryFrame {
+    /// Set when an include inside this try's `esi:attempt` fails without `alt` or
+    /// `continue_on_error` rescuing it.
+    failed: bool,
+    attempt_output: Vec<u8>,
+    except_output: Vec<u8>,
+    // Response headers merged by fragments inside each half, held here rather than in the
+    // real `MergedHeaders` accumulator until it's known whether this block's output (and so
+    // its headers) actually survives to the client.
+    attempt_headers: MergedHeaders,
+    except_headers: MergedHeaders,
+}
+
+/// Tracks nested `esi:try` blocks so that `esi:attempt` content can be buffered and rolled
+/// back in favor of `esi:except` if an include inside it fails.
+#[derive(Default)]
+struct TryState {
+    frames: Vec<TryFrame>,
+    /// Buffers currently capturing output instead of the real sink, innermost last. Empty
+    /// outside of any `esi:attempt`/`esi:except` block.
+    buffers: Vec<(Vec<u8>, BufferKind)>,
+}
+
+impl TryState {
+    /// Index into `frames` of the nearest enclosing `esi:attempt` block, i.e. the frame a hard
+    /// include failure right now should be caught by. `buffers[k]` always corresponds to
+    /// `frames[k]` when present (each frame contributes at most one open buffer at a time), so
+    /// this walks `buffers` from innermost outward, skipping over any open `esi:except` blocks,
+    /// until it finds an `esi:attempt` — an `esi:except` isn't itself protected by its own
+    /// `esi:try`, but a failure inside it can still be caught by an outer one.
+    fn enclosing_attempt_index(&self) -> Option<usize> {
+        self.buffers
+            .iter()
+            .rposition(|(_, kind)| matches!(kind, BufferKind::Attempt))
+    }
+
+    /// Whether a hard include failure right now should be caught by an enclosing `esi:try`
+    /// instead of propagating.
+    fn in_attempt(&self) -> bool {
+        self.enclosing_attempt_index().is_some()
+    }
+
+    /// Whether we're inside an `esi:attempt` whose enclosing `esi:try` has already failed, so
+    /// there's no point waiting on or recursing into further includes within it.
+    fn attempt_already_failed(&self) -> bool {
+        self.enclosing_attempt_index()
+            .and_then(|idx| self.frames.get(idx))
+            .is_some_and(|frame| frame.failed)
+    }
+}
+
+/// Cross-cutting state threaded through an entire `execute_esi` call tree: the pending-request
+/// dispatcher, the response-header accumulator, `esi:try` bookkeeping, and the count of
+/// subrequests currently in flight. Bundled into one struct, rather than passed as separate
+/// parameters, both to keep the fragment-processing functions' argument lists manageable and
+/// because `in_flight` specifically must be shared across every recursive call rather than
+/// reset per level — otherwise `max_concurrent_includes` would only bound the concurrency of
+/// one level of includes instead of the whole tree.
+struct ExecutionContext<'a> {
+    request_handler: &'a dyn Fn(Request, Option<Duration>) -> Result<PendingRequest>,
+    merged_headers: &'a mut MergedHeaders,
+    try_state: &'a mut TryState,
+    in_flight: &'a mut usize,
+}
+
+impl Processor {
+    /// Marks the `esi:try` block currently running its `esi:attempt` as failed, so that
+    /// `esi:except` is emitted in its place once the block closes.
+    fn fail_enclosing_attempt(try_state: &mut TryState) {
+        if let Some(idx) = try_state.enclosing_attempt_index() {
+            if let Some(frame) = try_state.frames.get_mut(idx) {
+                frame.failed = true;
+            }
+        }
+    }
+
+    /// The header accumulator that response headers merged right now should land in: the
+    /// innermost open `esi:attempt`/`esi:except` frame if one is in progress (since its
+    /// headers may yet be rolled back), otherwise the real accumulator headed for the
+    /// client.
+    fn header_sink<'a>(
+        try_state: &'a mut TryState,
+        merged_headers: &'a mut MergedHeaders,
+    ) -> &'a mut MergedHeaders {
+        let kind = try_state.buffers.last().map(|(_, kind)| *kind);
+        match (kind, try_state.frames.last_mut()) {
+            (Some(BufferKind::Attempt), Some(frame)) => &mut frame.attempt_headers,
+            (Some(BufferKind::Except), Some(frame)) => &mut frame.except_headers,
+            _ => merged_headers,
+        }
+    }
+
+    /// Appends `headers` onto `sink`, skipping any header name already present in `sink`.
+    /// The "already present" check runs once per header name rather than per value, so a
+    /// multi-valued header (e.g. `Set-Cookie`) keeps every value instead of only the first.
+    fn merge_into_sink(sink: &mut MergedHeaders, headers: MergedHeaders) {
+        let existing_names: HashSet<_> = sink.iter().map(|(name, _)| name.clone()).collect();
+        for (name, value) in headers {
+            if !existing_names.contains(&name) {
+                sink.push((name, value));
+            }
+        }
+    }
+}
+
 impl Processor {
     pub fn execute_esi(
         &self,
         original_request: Request,
         mut document: Response,
-        request_handler: &dyn Fn(Request) -> Result<Response>,
+        request_handler: &dyn Fn(Request, Option<Duration>) -> Result<PendingRequest>,
     ) -> Result<()> {
         // Create a parser for the ESI document
         let body = document.take_body();
         let xml_reader = Reader::from_reader(body);
 
+        if self.configuration.buffer_document {
+            // Buffer the whole document in memory so that an error partway through
+            // processing can still produce a proper status code and error body, rather
+            // than appending plain text to a response that has already been streamed.
+            let mut buffer = Vec::new();
+            let mut xml_writer = Writer::new(&mut buffer);
+            let mut merged_headers = MergedHeaders::new();
+            let mut try_state = TryState::default();
+            let mut in_flight = 0usize;
+            let mut ctx = ExecutionContext {
+                request_handler,
+                merged_headers: &mut merged_headers,
+                try_state: &mut try_state,
+                in_flight: &mut in_flight,
+            };
+
+            return match self.execute_esi_fragment(
+                original_request,
+                xml_reader,
+                &mut xml_writer,
+                &mut ctx,
+            ) {
+                Ok(_) => {
+                    // Headers haven't been sent to the client yet, so fragment response
+                    // headers collected along the way can still be merged in. `append_header`
+                    // (rather than `set_header`) so that a multi-valued header like
+                    // `Set-Cookie` keeps every value a fragment contributed instead of the
+                    // last one overwriting the rest.
+                    for (name, value) in merged_headers {
+                        document.append_header(name, value);
+                    }
+                    document.set_body(buffer);
+                    document.send_to_client();
+                    Ok(())
+                }
+                Err(err) => {
+                    error!("error executing ESI: {:?}", err);
+                    let status = match err {
+                        ExecutionError::FragmentTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+                        _ => StatusCode::BAD_GATEWAY,
+                    };
+                    document.set_status(status);
+                    document.set_body("An error occurred while constructing this document.\n");
+                    document.send_to_client();
+                    Err(err)
+                }
+            };
+        }
+
         // Send the response headers to the client and open an output stream
         let output = document.stream_to_client();
 
         // Set up an XML writer to write directly to the client output stream.
         let mut xml_writer = Writer::new(output);
 
-        // Parse the ESI document
-        match self.execute_esi_fragment(
-            original_request,
-            xml_reader,
-            &mut xml_writer,
+        // Response headers have already been sent above, so there's nowhere to merge
+        // fragment response headers into; `header_policy.merge_response_headers` only
+        // takes effect when `buffer_document` is enabled.
+        let mut merged_headers = MergedHeaders::new();
+        let mut try_state = TryState::default();
+        let mut in_flight = 0usize;
+        let mut ctx = ExecutionContext {
             request_handler,
-        ) {
+            merged_headers: &mut merged_headers,
+            try_state: &mut try_state,
+            in_flight: &mut in_flight,
+        };
+
+        // Parse the ESI document
+        match self.execute_esi_fragment(original_request, xml_reader, &mut xml_writer, &mut ctx) {
             Ok(_) => Ok(()),
             Err(err) => {
                 error!("error executing ESI: {:?}", err);
@@ -81,102 +302,352 @@ impl Processor {
         }
     }
 
-    pub fn execute_esi_fragment(
+    pub(crate) fn execute_esi_fragment<R: BufRead, W: Write>(
         &self,
         original_request: Request,
-        mut xml_reader: Reader<Body>,
-        xml_writer: &mut Writer<StreamingBody>,
-        request_handler: &dyn Fn(Request) -> Result<Response>,
+        mut xml_reader: Reader<R>,
+        xml_writer: &mut Writer<W>,
+        ctx: &mut ExecutionContext,
     ) -> Result<()> {
-        // Parse the ESI fragment
-        parse_tags(
-            &self.configuration.namespace,
-            &mut xml_reader,
-            &mut |event| {
-                match event {
-                    Event::ESI(Tag::Include {
-                        src,
-                        alt,
-                        continue_on_error,
-                    }) => {
-                        let resp = match self.send_esi_fragment_request(
-                            &original_request,
-                            &src,
-                            request_handler,
-                        ) {
-                            Ok(resp) => Some(resp),
-                            Err(err) => {
-                                warn!("Request to {} failed: {:?}", src, err);
-                                if let Some(alt) = alt {
-                                    warn!("Trying `alt` instead: {}", alt);
-                                    match self.send_esi_fragment_request(
-                                        &original_request,
-                                        &alt,
-                                        request_handler,
-                                    ) {
-                                        Ok(resp) => Some(resp),
-                                        Err(err) => {
-                                            debug!("Alt request to {} failed: {:?}", alt, err);
-                                            if continue_on_error {
-                                                None
-                                            } else {
-                                                return Err(err);
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    error!("Fragment request failed with no `alt` available");
-                                    if continue_on_error {
-                                        None
-                                    } else {
-                                        return Err(err);
-                                    }
-                                }
+        // Segments accumulate here in document order. Plain XML is queued alongside
+        // in-flight includes so that, even though sibling fragments are fetched
+        // concurrently, output is always written in source order.
+        let mut queue: VecDeque<Segment> = VecDeque::new();
+
+        // A parse error (e.g. an `esi:include` missing `src`) only ever fails to validate one
+        // tag; the underlying XML reader's position is untouched, so scanning can resume right
+        // after it. Loop so that, when an `esi:attempt` is open, such an error gets the same
+        // `esi:try` treatment as a failed fetch — the attempt is marked failed and the rest of
+        // the document (including the `esi:except` that rescues it) still gets scanned, rather
+        // than aborting the whole document on the first bad tag.
+        loop {
+            let result = parse_tags(
+                &self.configuration.namespace,
+                &mut xml_reader,
+                &mut |event| {
+                    match event {
+                        Event::Esi(Tag::Include {
+                            src,
+                            alt,
+                            continue_on_error,
+                            timeout,
+                        }) => {
+                            // Cap the number of in-flight subrequests by resolving the oldest
+                            // queued work (which may itself be a still-pending include) until a
+                            // slot frees up. `ctx.in_flight` is shared across the whole include
+                            // tree (not reset per recursive call), so this bounds concurrency
+                            // across every nesting level, not just this fragment's own includes.
+                            while *ctx.in_flight >= self.configuration.max_concurrent_includes {
+                                self.drain_one(&mut queue, &original_request, xml_writer, ctx)?;
                             }
-                        };
-
-                        if let Some(mut resp) = resp {
-                            let reader = Reader::from_reader(resp.take_body());
-                            self.execute_esi_fragment(
-                                original_request.clone_without_body(),
-                                reader,
-                                xml_writer,
-                                request_handler,
-                            )?;
-                        } else {
-                            error!("No content for fragment");
+
+                            let pending = self.dispatch_esi_fragment_request(
+                                &original_request,
+                                &src,
+                                timeout,
+                                ctx.request_handler,
+                            );
+                            *ctx.in_flight += 1;
+                            queue.push_back(Segment::Include(Box::new(PendingInclude {
+                                src,
+                                alt,
+                                continue_on_error,
+                                timeout,
+                                pending,
+                            })));
                         }
+                        Event::Xml(event) => {
+                            queue.push_back(Segment::Xml(event.into_owned()));
+                        }
+                        Event::Esi(Tag::TryStart) => queue.push_back(Segment::TryStart),
+                        Event::Esi(Tag::TryEnd) => queue.push_back(Segment::TryEnd),
+                        Event::Esi(Tag::AttemptStart) => queue.push_back(Segment::AttemptStart),
+                        Event::Esi(Tag::AttemptEnd) => queue.push_back(Segment::AttemptEnd),
+                        Event::Esi(Tag::ExceptStart) => queue.push_back(Segment::ExceptStart),
+                        Event::Esi(Tag::ExceptEnd) => queue.push_back(Segment::ExceptEnd),
                     }
-                    Event::XML(event) => {
-                        xml_writer.write_event(event)?;
-                        xml_writer.inner().flush().expect("failed to flush output");
+                    Ok(())
+                },
+            );
+
+            let Err(err) = result else { break };
+
+            // Drain whatever was already queued ahead of the bad tag, so that any
+            // `esi:try`/`esi:attempt` it opened is actually applied to `ctx.try_state` before
+            // checking whether this error is inside one.
+            while !queue.is_empty() {
+                self.drain_one(&mut queue, &original_request, xml_writer, ctx)?;
+            }
+
+            if ctx.try_state.in_attempt() {
+                warn!("Parse error inside esi:attempt, falling back to esi:except: {:?}", err);
+                Self::fail_enclosing_attempt(ctx.try_state);
+            } else {
+                return Err(err);
+            }
+        }
+
+        while !queue.is_empty() {
+            self.drain_one(&mut queue, &original_request, xml_writer, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pops the front of the queue and resolves it: plain XML is written straight through
+    /// (or into the innermost open `esi:attempt`/`esi:except` buffer), a pending include is
+    /// waited on and recursed into, and `esi:try` markers update `ctx.try_state`.
+    fn drain_one<W: Write>(
+        &self,
+        queue: &mut VecDeque<Segment>,
+        original_request: &Request,
+        xml_writer: &mut Writer<W>,
+        ctx: &mut ExecutionContext,
+    ) -> Result<()> {
+        match queue.pop_front() {
+            Some(Segment::Xml(event)) => {
+                if let Some((buf, _)) = ctx.try_state.buffers.last_mut() {
+                    Writer::new(buf).write_event(event)?;
+                } else {
+                    xml_writer.write_event(event)?;
+                    xml_writer.inner().flush().expect("failed to flush output");
+                }
+            }
+            Some(Segment::Include(inc)) => {
+                *ctx.in_flight -= 1;
+                if ctx.try_state.attempt_already_failed() {
+                    // This esi:try's attempt has already failed and its buffered output will
+                    // be thrown away in favor of esi:except, so there's no point waiting on
+                    // or recursing into the rest of its includes.
+                    drop(inc.pending);
+                } else {
+                    self.resolve_pending_include(*inc, original_request, xml_writer, ctx)?;
+                }
+            }
+            Some(Segment::TryStart) => ctx.try_state.frames.push(TryFrame::default()),
+            Some(Segment::AttemptStart) => {
+                ctx.try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+            }
+            Some(Segment::AttemptEnd) => {
+                if let Some((buf, BufferKind::Attempt)) = ctx.try_state.buffers.pop() {
+                    if let Some(frame) = ctx.try_state.frames.last_mut() {
+                        frame.attempt_output = buf;
                     }
                 }
-                Ok(())
-            },
-        )?;
+            }
+            Some(Segment::ExceptStart) => {
+                ctx.try_state.buffers.push((Vec::new(), BufferKind::Except));
+            }
+            Some(Segment::ExceptEnd) => {
+                if let Some((buf, BufferKind::Except)) = ctx.try_state.buffers.pop() {
+                    if let Some(frame) = ctx.try_state.frames.last_mut() {
+                        frame.except_output = buf;
+                    }
+                }
+            }
+            Some(Segment::TryEnd) => {
+                if let Some(frame) = ctx.try_state.frames.pop() {
+                    let (output, headers) = if frame.failed {
+                        (frame.except_output, frame.except_headers)
+                    } else {
+                        (frame.attempt_output, frame.attempt_headers)
+                    };
+                    self.emit_raw(&output, xml_writer, ctx.try_state)?;
+                    let sink = Self::header_sink(ctx.try_state, ctx.merged_headers);
+                    Self::merge_into_sink(sink, headers);
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Writes already-serialized XML straight through, or into the innermost open
+    /// `esi:attempt`/`esi:except` buffer if a nested `esi:try` is still in progress. Used to
+    /// commit the winning half of a resolved `esi:try` block.
+    fn emit_raw<W: Write>(
+        &self,
+        bytes: &[u8],
+        xml_writer: &mut Writer<W>,
+        try_state: &mut TryState,
+    ) -> Result<()> {
+        if let Some((buf, _)) = try_state.buffers.last_mut() {
+            buf.extend_from_slice(bytes);
+        } else {
+            xml_writer
+                .inner()
+                .write_all(bytes)
+                .expect("failed to write buffered output");
+            xml_writer.inner().flush().expect("failed to flush output");
+        }
+        Ok(())
+    }
+
+    /// Resolves a dispatched include: falls back to `alt` and honors `continue_on_error`
+    /// exactly as a synchronous fetch would, then recurses into the resolved body.
+    fn resolve_pending_include<W: Write>(
+        &self,
+        inc: PendingInclude,
+        original_request: &Request,
+        xml_writer: &mut Writer<W>,
+        ctx: &mut ExecutionContext,
+    ) -> Result<()> {
+        let PendingInclude {
+            src,
+            alt,
+            continue_on_error,
+            timeout,
+            pending,
+        } = inc;
+
+        let resp = match pending.and_then(|p| Self::wait_esi_fragment_request(&src, p)) {
+            Ok(resp) => Some(resp),
+            Err(err) => {
+                warn!("Request to {} failed: {:?}", src, err);
+                if let Some(alt) = alt {
+                    warn!("Trying `alt` instead: {}", alt);
+                    match self
+                        .dispatch_esi_fragment_request(
+                            original_request,
+                            &alt,
+                            timeout,
+                            ctx.request_handler,
+                        )
+                        .and_then(|p| Self::wait_esi_fragment_request(&alt, p))
+                    {
+                        Ok(resp) => Some(resp),
+                        Err(err) => {
+                            debug!("Alt request to {} failed: {:?}", alt, err);
+                            if continue_on_error {
+                                None
+                            } else if ctx.try_state.in_attempt() {
+                                Self::fail_enclosing_attempt(ctx.try_state);
+                                None
+                            } else {
+                                return Err(err);
+                            }
+                        }
+                    }
+                } else {
+                    error!("Fragment request failed with no `alt` available");
+                    if continue_on_error {
+                        None
+                    } else if ctx.try_state.in_attempt() {
+                        Self::fail_enclosing_attempt(ctx.try_state);
+                        None
+                    } else {
+                        return Err(err);
+                    }
+                }
+            }
+        };
+
+        if let Some(mut resp) = resp {
+            let sink = Self::header_sink(ctx.try_state, ctx.merged_headers);
+            self.merge_response_headers(&resp, sink);
+
+            let reader = Reader::from_reader(resp.take_body());
+            if let Err(err) = self.execute_esi_fragment(
+                original_request.clone_without_body(),
+                reader,
+                xml_writer,
+                ctx,
+            ) {
+                // As with a parse error in this fragment's own markup, an error processing a
+                // fetched fragment's body (bad markup, a timeout, a failed nested include)
+                // falls back to `esi:except` when one is open, rather than aborting the whole
+                // document.
+                if ctx.try_state.in_attempt() {
+                    warn!(
+                        "Error processing fragment body for {} inside esi:attempt, falling back to esi:except: {:?}",
+                        src, err
+                    );
+                    Self::fail_enclosing_attempt(ctx.try_state);
+                } else {
+                    return Err(err);
+                }
+            }
+        } else {
+            error!("No content for fragment");
+        }
 
         Ok(())
     }
 
-    fn send_esi_fragment_request(
+    /// Copies the configured response headers from a fragment response into the
+    /// accumulator, skipping any header already set by an earlier fragment. Multi-valued
+    /// headers (e.g. `Set-Cookie`) have every value from the winning fragment copied, not
+    /// just the first.
+    fn merge_response_headers(&self, resp: &Response, merged_headers: &mut MergedHeaders) {
+        for name in &self.configuration.header_policy.merge_response_headers {
+            if merged_headers.iter().any(|(merged, _)| merged == name) {
+                continue;
+            }
+            for value in resp.get_header_all(name) {
+                merged_headers.push((name.clone(), value.clone()));
+            }
+        }
+    }
+
+    /// Sends a fragment subrequest without waiting for it to complete, so that siblings can
+    /// be dispatched while it is still in flight.
+    fn dispatch_esi_fragment_request(
         &self,
         original_request: &Request,
         url: &str,
-        request_handler: &dyn Fn(Request) -> Result<Response>,
-    ) -> Result<Response> {
+        timeout: Option<Duration>,
+        request_handler: &dyn Fn(Request, Option<Duration>) -> Result<PendingRequest>,
+    ) -> Result<PendingRequest> {
         let mut req = original_request
             .clone_without_body()
             .with_url(url)
             .with_pass(true);
 
+        self.apply_request_header_policy(&mut req);
+
         let hostname = req.get_url().host().expect("no host").to_string();
 
         req.set_header(header::HOST, &hostname);
 
         debug!("Requesting ESI fragment: {}", url);
 
-        let resp = request_handler(req)?;
+        // This SDK only exposes fetch timeouts at backend configuration (connect/first-byte/
+        // between-bytes timeouts), not per request, so there's no `Request` method to call
+        // here. A per-include timeout overrides the configuration-wide default; whichever
+        // applies is handed to `request_handler`, which owns backend selection and so is the only place
+        // that can actually apply one (e.g. by routing to a backend configured with a
+        // matching timeout).
+        request_handler(req, timeout.or(self.configuration.timeout))
+    }
+
+    /// Trims the subrequest's headers down to what `header_policy.forward_request_headers`
+    /// allows, before `Host` is set.
+    fn apply_request_header_policy(&self, req: &mut Request) {
+        let policy = &self.configuration.header_policy.forward_request_headers;
+        let to_remove: Vec<HeaderName> = match policy {
+            HeaderList::Allow(allowed) => req
+                .get_header_names()
+                .filter(|name| !allowed.iter().any(|allowed| allowed == *name))
+                .cloned()
+                .collect(),
+            HeaderList::Deny(denied) => denied.clone(),
+        };
+
+        for name in to_remove {
+            req.remove_header(name);
+        }
+    }
+
+    /// Blocks on a previously-dispatched fragment subrequest and validates its response.
+    fn wait_esi_fragment_request(url: &str, pending: PendingRequest) -> Result<Response> {
+        let resp = match pending.wait() {
+            Ok(resp) => resp,
+            Err(err) if is_timeout_cause(&err) => {
+                return Err(ExecutionError::FragmentTimeout(url.to_string()))
+            }
+            Err(err) => return Err(ExecutionError::from(err)),
+        };
+
         if resp.get_status().is_success() {
             Ok(resp)
         } else {
@@ -184,3 +655,350 @@ impl Processor {
         }
     }
 }
+
+/// Whether a failed send's root cause was a timeout (e.g. a backend's configured
+/// connect/first-byte/between-bytes timeout was exceeded), as opposed to some other send
+/// failure. Matches on the SDK's own `SendErrorCause` variant rather than the `Debug`
+/// rendering of the error, since formatted text isn't a stable contract and could silently
+/// stop matching if the wording ever changes, quietly turning every timeout into a generic
+/// `RequestError` instead of the dedicated `FragmentTimeout`.
+fn is_timeout_cause(err: &SendError) -> bool {
+    matches!(
+        err.root_cause(),
+        SendErrorCause::DnsTimeout
+            | SendErrorCause::ConnectionTimeout
+            | SendErrorCause::HttpResponseTimeout
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn processor_new_normalizes_zero_max_concurrent_includes() {
+        let processor = Processor::new(Configuration {
+            max_concurrent_includes: 0,
+            ..Configuration::default()
+        });
+        assert_eq!(processor.configuration.max_concurrent_includes, 1);
+    }
+
+    #[test]
+    fn processor_new_leaves_nonzero_max_concurrent_includes_alone() {
+        let processor = Processor::new(Configuration {
+            max_concurrent_includes: 4,
+            ..Configuration::default()
+        });
+        assert_eq!(processor.configuration.max_concurrent_includes, 4);
+    }
+
+    #[test]
+    fn try_state_in_attempt_tracks_innermost_buffer() {
+        let mut try_state = TryState::default();
+        assert!(!try_state.in_attempt());
+
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+        assert!(try_state.in_attempt());
+    }
+
+    #[test]
+    fn try_state_in_attempt_sees_through_nested_except() {
+        // Outer `esi:try`'s `esi:attempt` is running; nested inside it, a second `esi:try`
+        // has moved on to its own `esi:except`. A failure right now (e.g. an include inside
+        // that nested except) still belongs to the outer attempt, since the nested except
+        // isn't itself protected by its own try.
+        let mut try_state = TryState::default();
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Except));
+
+        assert!(try_state.in_attempt());
+        assert_eq!(try_state.enclosing_attempt_index(), Some(0));
+    }
+
+    #[test]
+    fn fail_enclosing_attempt_marks_innermost_frame_failed() {
+        let mut try_state = TryState::default();
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+        assert!(!try_state.attempt_already_failed());
+
+        Processor::fail_enclosing_attempt(&mut try_state);
+        assert!(try_state.attempt_already_failed());
+    }
+
+    #[test]
+    fn fail_enclosing_attempt_marks_outer_frame_through_nested_except() {
+        let mut try_state = TryState::default();
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Except));
+
+        Processor::fail_enclosing_attempt(&mut try_state);
+        assert!(try_state.frames[0].failed);
+        assert!(!try_state.frames[1].failed);
+    }
+
+    #[test]
+    fn header_sink_prefers_innermost_try_frame_over_real_accumulator() {
+        let mut try_state = TryState::default();
+        let mut merged_headers = MergedHeaders::new();
+        let header = (HeaderName::from_static("x-test"), HeaderValue::from_static("1"));
+
+        // Outside any esi:try, headers land in the real accumulator headed for the client.
+        Processor::header_sink(&mut try_state, &mut merged_headers).push(header.clone());
+        assert_eq!(merged_headers.len(), 1);
+
+        // Inside an esi:attempt, headers land in that frame instead, since they may yet be
+        // rolled back in favor of esi:except.
+        merged_headers.clear();
+        try_state.frames.push(TryFrame::default());
+        try_state.buffers.push((Vec::new(), BufferKind::Attempt));
+        Processor::header_sink(&mut try_state, &mut merged_headers).push(header);
+        assert!(merged_headers.is_empty());
+        assert_eq!(try_state.frames[0].attempt_headers.len(), 1);
+    }
+
+    #[test]
+    fn merge_into_sink_keeps_every_value_of_a_multi_valued_header() {
+        let mut sink: MergedHeaders = Vec::new();
+        let headers: MergedHeaders = vec![
+            (HeaderName::from_static("set-cookie"), HeaderValue::from_static("a=1")),
+            (HeaderName::from_static("set-cookie"), HeaderValue::from_static("b=2")),
+        ];
+
+        Processor::merge_into_sink(&mut sink, headers);
+
+        assert_eq!(
+            sink,
+            vec![
+                (HeaderName::from_static("set-cookie"), HeaderValue::from_static("a=1")),
+                (HeaderName::from_static("set-cookie"), HeaderValue::from_static("b=2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_request_header_policy_allow_keeps_only_named_headers() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                forward_request_headers: HeaderList::Allow(vec![HeaderName::from_static(
+                    "cookie",
+                )]),
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let mut req = Request::new("GET", "https://example.com/")
+            .with_header("cookie", "session=1")
+            .with_header("authorization", "Bearer secret")
+            .with_header("x-geo", "se");
+
+        processor.apply_request_header_policy(&mut req);
+
+        assert_eq!(req.get_header_names().count(), 1);
+        assert_eq!(req.get_header_str("cookie"), Some("session=1"));
+    }
+
+    #[test]
+    fn apply_request_header_policy_empty_allow_strips_everything() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                forward_request_headers: HeaderList::Allow(Vec::new()),
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let mut req = Request::new("GET", "https://example.com/").with_header("cookie", "a=1");
+
+        processor.apply_request_header_policy(&mut req);
+
+        assert_eq!(req.get_header_names().count(), 0);
+    }
+
+    #[test]
+    fn apply_request_header_policy_deny_strips_only_named_headers() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                forward_request_headers: HeaderList::Deny(vec![HeaderName::from_static(
+                    "authorization",
+                )]),
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let mut req = Request::new("GET", "https://example.com/")
+            .with_header("cookie", "session=1")
+            .with_header("authorization", "Bearer secret")
+            .with_header("x-geo", "se");
+
+        processor.apply_request_header_policy(&mut req);
+
+        assert_eq!(req.get_header_names().count(), 2);
+        assert_eq!(req.get_header_str("cookie"), Some("session=1"));
+        assert_eq!(req.get_header_str("x-geo"), Some("se"));
+        assert_eq!(req.get_header_str("authorization"), None);
+    }
+
+    #[test]
+    fn apply_request_header_policy_default_deny_forwards_everything() {
+        let processor = Processor::new(Configuration::default());
+        let mut req = Request::new("GET", "https://example.com/")
+            .with_header("cookie", "session=1")
+            .with_header("authorization", "Bearer secret");
+
+        processor.apply_request_header_policy(&mut req);
+
+        assert_eq!(req.get_header_names().count(), 2);
+        assert_eq!(req.get_header_str("cookie"), Some("session=1"));
+        assert_eq!(req.get_header_str("authorization"), Some("Bearer secret"));
+    }
+
+    #[test]
+    fn merge_response_headers_copies_only_configured_names() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                merge_response_headers: vec![HeaderName::from_static("cache-control")],
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let resp = Response::new()
+            .with_header("cache-control", "max-age=60")
+            .with_header("x-internal", "should-not-merge");
+        let mut merged_headers = MergedHeaders::new();
+
+        processor.merge_response_headers(&resp, &mut merged_headers);
+
+        assert_eq!(
+            merged_headers,
+            vec![(
+                HeaderName::from_static("cache-control"),
+                HeaderValue::from_static("max-age=60")
+            )]
+        );
+    }
+
+    #[test]
+    fn merge_response_headers_keeps_every_value_of_a_multi_valued_header() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                merge_response_headers: vec![HeaderName::from_static("set-cookie")],
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let mut resp = Response::new().with_header("set-cookie", "a=1");
+        resp.append_header("set-cookie", "b=2");
+        let mut merged_headers = MergedHeaders::new();
+
+        processor.merge_response_headers(&resp, &mut merged_headers);
+
+        assert_eq!(
+            merged_headers,
+            vec![
+                (HeaderName::from_static("set-cookie"), HeaderValue::from_static("a=1")),
+                (HeaderName::from_static("set-cookie"), HeaderValue::from_static("b=2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_response_headers_honors_first_fragment_wins() {
+        let processor = Processor::new(Configuration {
+            header_policy: HeaderPolicy {
+                merge_response_headers: vec![HeaderName::from_static("cache-control")],
+                ..HeaderPolicy::default()
+            },
+            ..Configuration::default()
+        });
+        let resp = Response::new().with_header("cache-control", "max-age=5");
+        let mut merged_headers: MergedHeaders = vec![(
+            HeaderName::from_static("cache-control"),
+            HeaderValue::from_static("max-age=60"),
+        )];
+
+        processor.merge_response_headers(&resp, &mut merged_headers);
+
+        assert_eq!(
+            merged_headers,
+            vec![(
+                HeaderName::from_static("cache-control"),
+                HeaderValue::from_static("max-age=60")
+            )]
+        );
+    }
+
+    #[test]
+    fn merge_into_sink_skips_a_name_already_present_in_the_sink() {
+        let mut sink: MergedHeaders = vec![(
+            HeaderName::from_static("set-cookie"),
+            HeaderValue::from_static("existing=1"),
+        )];
+        let headers: MergedHeaders = vec![(
+            HeaderName::from_static("set-cookie"),
+            HeaderValue::from_static("new=2"),
+        )];
+
+        Processor::merge_into_sink(&mut sink, headers);
+
+        assert_eq!(
+            sink,
+            vec![(
+                HeaderName::from_static("set-cookie"),
+                HeaderValue::from_static("existing=1")
+            )]
+        );
+    }
+
+    /// Drives `execute_esi_fragment` (and so `drain_one`/`resolve_pending_include`) over a real
+    /// document with a nested `esi:try`, using a `request_handler` stub that fails every
+    /// dispatch. Failing the stub synchronously means `PendingInclude.pending` is `Err(..)`, so
+    /// no actual `PendingRequest` (which only the Fastly host can produce) is ever needed.
+    #[test]
+    fn nested_try_failure_is_caught_by_the_outer_attempt() {
+        // No whitespace between tags: text directly between structural tags would otherwise be
+        // written straight to the real output rather than buffered (since no `esi:attempt`/
+        // `esi:except` buffer is open yet at that exact point), which has nothing to do with
+        // the rollback behavior under test here.
+        let document = concat!(
+            "<esi:try><esi:attempt>outer-before",
+            "<esi:try>",
+            "<esi:attempt><esi:include src=\"https://example.com/inner\"/></esi:attempt>",
+            "<esi:except><esi:include src=\"https://example.com/inner-except\"/></esi:except>",
+            "</esi:try>",
+            "outer-after</esi:attempt>",
+            "<esi:except>outer-rescued</esi:except></esi:try>",
+        );
+
+        let processor = Processor::new(Configuration::default());
+        let request_handler: &dyn Fn(Request, Option<Duration>) -> Result<PendingRequest> =
+            &|_req, _timeout| Err(ExecutionError::Unknown);
+
+        let original_request = Request::new("GET", "https://example.com/");
+        let xml_reader = Reader::from_reader(Cursor::new(document.as_bytes()));
+        let mut output = Vec::new();
+        let mut xml_writer = Writer::new(&mut output);
+        let mut merged_headers = MergedHeaders::new();
+        let mut try_state = TryState::default();
+        let mut in_flight = 0usize;
+        let mut ctx = ExecutionContext {
+            request_handler,
+            merged_headers: &mut merged_headers,
+            try_state: &mut try_state,
+            in_flight: &mut in_flight,
+        };
+
+        processor
+            .execute_esi_fragment(original_request, xml_reader, &mut xml_writer, &mut ctx)
+            .expect("a failed include inside esi:try should be caught, not propagated");
+
+        assert_eq!(String::from_utf8(output).unwrap().trim(), "outer-rescued");
+        assert_eq!(*ctx.in_flight, 0);
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::BufRead;
+use std::time::Duration;
+
+use log::warn;
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use quick_xml::Reader;
+
+use crate::{ExecutionError, Result};
+
+/// A recognized ESI tag, extracted from the surrounding document markup.
+#[derive(Debug, Clone)]
+pub enum Tag {
+    /// `<esi:include src="..." alt="..." onerror="continue" timeout="..."/>`
+    Include {
+        src: String,
+        alt: Option<String>,
+        continue_on_error: bool,
+        timeout: Option<Duration>,
+    },
+    /// `<esi:try>`
+    TryStart,
+    /// `</esi:try>`
+    TryEnd,
+    /// `<esi:attempt>`
+    AttemptStart,
+    /// `</esi:attempt>`
+    AttemptEnd,
+    /// `<esi:except>`
+    ExceptStart,
+    /// `</esi:except>`
+    ExceptEnd,
+}
+
+/// An event produced while scanning a document for ESI tags.
+pub enum Event<'a> {
+    /// A recognized ESI tag.
+    Esi(Tag),
+    /// Markup outside of any recognized ESI tag, passed through unchanged.
+    Xml(XmlEvent<'a>),
+}
+
+pub fn parse_tags<R: BufRead>(
+    namespace: &str,
+    reader: &mut Reader<R>,
+    handler: &mut dyn FnMut(Event) -> Result<()>,
+) -> Result<()> {
+    let include_tag = format!("{}:include", namespace).into_bytes();
+    let try_tag = format!("{}:try", namespace).into_bytes();
+    let attempt_tag = format!("{}:attempt", namespace).into_bytes();
+    let except_tag = format!("{}:except", namespace).into_bytes();
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        match reader.read_event(&mut buf)? {
+            XmlEvent::Eof => break,
+            XmlEvent::Empty(tag) if tag.name() == include_tag.as_slice() => {
+                handler(Event::Esi(parse_include(&tag)?))?;
+            }
+            XmlEvent::Start(tag) if tag.name() == try_tag.as_slice() => {
+                handler(Event::Esi(Tag::TryStart))?;
+            }
+            XmlEvent::End(tag) if tag.name() == try_tag.as_slice() => {
+                handler(Event::Esi(Tag::TryEnd))?;
+            }
+            XmlEvent::Start(tag) if tag.name() == attempt_tag.as_slice() => {
+                handler(Event::Esi(Tag::AttemptStart))?;
+            }
+            XmlEvent::End(tag) if tag.name() == attempt_tag.as_slice() => {
+                handler(Event::Esi(Tag::AttemptEnd))?;
+            }
+            XmlEvent::Start(tag) if tag.name() == except_tag.as_slice() => {
+                handler(Event::Esi(Tag::ExceptStart))?;
+            }
+            XmlEvent::End(tag) if tag.name() == except_tag.as_slice() => {
+                handler(Event::Esi(Tag::ExceptEnd))?;
+            }
+            event => handler(Event::Xml(event))?,
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_include(tag: &BytesStart) -> Result<Tag> {
+    let mut src = None;
+    let mut alt = None;
+    let mut continue_on_error = false;
+    let mut timeout = None;
+
+    for attr in tag.attributes() {
+        let attr = attr?;
+        let value = String::from_utf8_lossy(&attr.unescaped_value()?).into_owned();
+        match attr.key {
+            b"src" => src = Some(value),
+            b"alt" => alt = Some(value),
+            b"onerror" if value == "continue" => continue_on_error = true,
+            b"timeout" => timeout = parse_timeout(&value),
+            _ => {}
+        }
+    }
+
+    let src = src.ok_or_else(|| {
+        ExecutionError::MissingRequiredParameter("esi:include".to_string(), "src".to_string())
+    })?;
+
+    Ok(Tag::Include {
+        src,
+        alt,
+        continue_on_error,
+        timeout,
+    })
+}
+
+/// Parses a plain number of seconds, e.g. `timeout="2"` or `timeout="0.5"`. Malformed values
+/// (including negative, NaN, infinite, and out-of-range ones, which `Duration::from_secs_f64`
+/// would panic on) are logged and ignored rather than failing the whole fragment.
+fn parse_timeout(value: &str) -> Option<Duration> {
+    match value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+    {
+        Some(timeout) => Some(timeout),
+        None => {
+            warn!("ignoring malformed `timeout` value: {}", value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn include_tag(attrs: &str) -> BytesStart<'static> {
+        let content = format!("esi:include {}", attrs);
+        BytesStart::owned(content.into_bytes(), "esi:include".len())
+    }
+
+    #[test]
+    fn parse_include_requires_src() {
+        let tag = include_tag(r#"alt="/fallback""#);
+        let err = parse_include(&tag).unwrap_err();
+        assert!(matches!(err, ExecutionError::MissingRequiredParameter(_, _)));
+    }
+
+    #[test]
+    fn parse_include_reads_all_attributes() {
+        let tag = include_tag(r#"src="/a" alt="/b" onerror="continue" timeout="2.5""#);
+        match parse_include(&tag).unwrap() {
+            Tag::Include {
+                src,
+                alt,
+                continue_on_error,
+                timeout,
+            } => {
+                assert_eq!(src, "/a");
+                assert_eq!(alt.as_deref(), Some("/b"));
+                assert!(continue_on_error);
+                assert_eq!(timeout, Some(Duration::from_secs_f64(2.5)));
+            }
+            other => panic!("expected Tag::Include, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_include_defaults_alt_and_onerror() {
+        let tag = include_tag(r#"src="/a""#);
+        match parse_include(&tag).unwrap() {
+            Tag::Include {
+                alt,
+                continue_on_error,
+                ..
+            } => {
+                assert_eq!(alt, None);
+                assert!(!continue_on_error);
+            }
+            other => panic!("expected Tag::Include, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_timeout_attribute_is_ignored_rather_than_failing_the_include() {
+        let tag = include_tag(r#"src="/a" timeout="not-a-number""#);
+        match parse_include(&tag).unwrap() {
+            Tag::Include { timeout, .. } => assert_eq!(timeout, None),
+            other => panic!("expected Tag::Include, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_timeout_accepts_plain_seconds() {
+        assert_eq!(parse_timeout("3"), Some(Duration::from_secs_f64(3.0)));
+    }
+
+    #[test]
+    fn parse_timeout_rejects_malformed_values() {
+        assert_eq!(parse_timeout("soon"), None);
+    }
+
+    #[test]
+    fn parse_timeout_rejects_negative_nan_and_infinite_values() {
+        assert_eq!(parse_timeout("-1"), None);
+        assert_eq!(parse_timeout("NaN"), None);
+        assert_eq!(parse_timeout("inf"), None);
+        assert_eq!(parse_timeout("infinity"), None);
+    }
+
+    #[test]
+    fn parse_timeout_rejects_values_too_large_for_duration() {
+        assert_eq!(parse_timeout("1e300"), None);
+    }
+}